@@ -59,18 +59,89 @@ pub enum NodeData{
     },
 }
 
+#[derive(Debug)]
+pub struct ParseError {
+    pub message: Cow<'static, str>,
+    // html5ever's TreeSink::parse_error only hands us the message, not a
+    // position, so these stay None until the tokenizer starts threading one
+    // through.
+    pub line: Option<u64>,
+    pub column: Option<u64>,
+}
+
+/// Mirrors kuchiki's `ParseOpts` split into tokenizer and tree-builder
+/// knobs, narrowed down to the options it's actually worth exposing to
+/// Elixir callers. `scripting_enabled` in particular controls whether
+/// `<noscript>` contents are parsed as text (scripting on) or markup
+/// (scripting off, the default here; html5ever's own default is on) -- it materially
+/// changes what a scraper sees.
+#[derive(Debug, Clone)]
+pub struct FlatSinkOpts {
+    pub scripting_enabled: bool,
+    pub drop_doctype: bool,
+    pub quirks_mode: QuirksMode,
+    pub exact_errors: bool,
+    pub discard_bom: bool,
+    pub profile: bool,
+}
+
+impl Default for FlatSinkOpts {
+    fn default() -> Self {
+        FlatSinkOpts {
+            // Unlike html5ever's own TreeBuilderOpts default (scripting
+            // on), scripting is off here, so `<noscript>` contents parse as
+            // markup, which is what a scraper wants to see.
+            scripting_enabled: false,
+            drop_doctype: false,
+            quirks_mode: QuirksMode::NoQuirks,
+            exact_errors: false,
+            discard_bom: true,
+            profile: false,
+        }
+    }
+}
+
+impl FlatSinkOpts {
+    fn to_parse_opts(&self) -> html5ever::driver::ParseOpts {
+        html5ever::driver::ParseOpts {
+            tokenizer: html5ever::tokenizer::TokenizerOpts {
+                exact_errors: self.exact_errors,
+                discard_bom: self.discard_bom,
+                profile: self.profile,
+                ..Default::default()
+            },
+            tree_builder: html5ever::tree_builder::TreeBuilderOpts {
+                scripting_enabled: self.scripting_enabled,
+                drop_doctype: self.drop_doctype,
+                quirks_mode: self.quirks_mode,
+                ..Default::default()
+            },
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct FlatSink {
     pub root: NodeHandle,
     pub nodes: Vec<Node>,
+    pub errors: Vec<ParseError>,
+    pub quirks_mode: QuirksMode,
+    opts: FlatSinkOpts,
 }
 
 impl FlatSink {
 
     pub fn new() -> FlatSink {
+        FlatSink::new_with_opts(FlatSinkOpts::default())
+    }
+
+    pub fn new_with_opts(opts: FlatSinkOpts) -> FlatSink {
         let mut sink = FlatSink {
             root: NodeHandle(0),
             nodes: Vec::with_capacity(200),
+            errors: Vec::new(),
+            quirks_mode: opts.quirks_mode,
+            opts: opts,
         };
 
         // Element 0 is always root
@@ -79,6 +150,45 @@ impl FlatSink {
         sink
     }
 
+    /// Parses a full document, honoring `opts`'s tokenizer/tree-builder
+    /// configuration instead of hardwired html5ever defaults.
+    pub fn parse_document(input: &[u8], opts: FlatSinkOpts) -> FlatSink {
+        let parse_opts = opts.to_parse_opts();
+        let sink = FlatSink::new_with_opts(opts);
+        html5ever::parse_document(sink, parse_opts).from_utf8().one(input)
+    }
+
+    /// Parses `input` as a fragment in the insertion mode implied by
+    /// `context_name`/`context_attrs` (e.g. `td`, `tr`, `select`,
+    /// `template`), rather than wrapping it in an implied `<html><body>`
+    /// the way `parse_document` would. html5ever's fragment algorithm
+    /// appends a synthetic `<html>` root element under the document node
+    /// and parses into it in the context element's insertion mode (the
+    /// context element itself is created but never inserted), so the
+    /// returned sink is re-rooted at that synthesized container and its
+    /// children are exactly the nodes the fragment produced.
+    pub fn parse_fragment(
+        input: &[u8],
+        context_name: QualName,
+        context_attrs: Vec<Attribute>,
+        opts: FlatSinkOpts,
+    ) -> FlatSink {
+        let parse_opts = opts.to_parse_opts();
+        let sink = FlatSink::new_with_opts(opts);
+        let mut sink = html5ever::parse_fragment(
+            sink,
+            parse_opts,
+            context_name,
+            context_attrs,
+        ).from_utf8().one(input);
+
+        if let Some(&container) = sink.node(sink.root).children.first() {
+            sink.root = container;
+        }
+
+        sink
+    }
+
     pub fn node_mut<'a>(&'a mut self, handle: NodeHandle) -> &'a mut Node {
         &mut self.nodes[handle.0]
     }
@@ -93,6 +203,131 @@ impl FlatSink {
         id
     }
 
+    // Walks the arena rooted at `handle` back into an HTML string. Uses an
+    // explicit stack rather than recursion since the arena can be arbitrarily
+    // deep and we don't want to blow the native stack on pathological input.
+    pub fn serialize(&self, handle: NodeHandle) -> String {
+        let mut out = String::new();
+        let mut stack = vec![SerializeOp::Visit(handle)];
+
+        while let Some(op) = stack.pop() {
+            match op {
+                SerializeOp::CloseTag(name) => {
+                    out.push_str("</");
+                    out.push_str(&name);
+                    out.push('>');
+                }
+                SerializeOp::Visit(handle) => {
+                    let node = self.node(handle);
+                    match node.data {
+                        NodeData::Document => {
+                            for &child in node.children.iter().rev() {
+                                stack.push(SerializeOp::Visit(child));
+                            }
+                        }
+                        NodeData::DocType { ref name, .. } => {
+                            out.push_str("<!DOCTYPE ");
+                            out.push_str(&name);
+                            out.push('>');
+                        }
+                        NodeData::Comment { ref contents } => {
+                            out.push_str("<!--");
+                            out.push_str(&contents);
+                            out.push_str("-->");
+                        }
+                        NodeData::Text { ref contents } => {
+                            let raw = node.parent
+                                .map(|parent| is_raw_text_element(self.elem_local_name(parent)))
+                                .unwrap_or(false);
+                            if raw {
+                                out.push_str(&contents);
+                            } else {
+                                out.push_str(&escape_text(&contents));
+                            }
+                        }
+                        NodeData::ProcessingInstruction { .. } => {}
+                        NodeData::Element { ref name, ref attrs, .. } => {
+                            let local_name = &*name.local;
+
+                            out.push('<');
+                            out.push_str(local_name);
+                            for attr in attrs {
+                                out.push(' ');
+                                out.push_str(&attr.name.local);
+                                out.push_str("=\"");
+                                out.push_str(&escape_attr_value(&attr.value));
+                                out.push('"');
+                            }
+
+                            if is_void_element(local_name) {
+                                out.push_str(" />");
+                            } else {
+                                out.push('>');
+                                stack.push(SerializeOp::CloseTag(local_name.to_string()));
+                                for &child in node.children.iter().rev() {
+                                    stack.push(SerializeOp::Visit(child));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        out
+    }
+
+    fn elem_local_name(&self, handle: NodeHandle) -> &str {
+        match self.node(handle).data {
+            NodeData::Element { ref name, .. } => &name.local,
+            _ => "",
+        }
+    }
+
+}
+
+enum SerializeOp {
+    Visit(NodeHandle),
+    CloseTag(String),
+}
+
+const HTML_VOID_ELEMENTS: &'static [&'static str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input",
+    "link", "meta", "param", "source", "track", "wbr",
+];
+
+const HTML_RAW_TEXT_ELEMENTS: &'static [&'static str] = &["script", "style"];
+
+fn is_void_element(local_name: &str) -> bool {
+    HTML_VOID_ELEMENTS.contains(&local_name)
+}
+
+fn is_raw_text_element(local_name: &str) -> bool {
+    HTML_RAW_TEXT_ELEMENTS.contains(&local_name)
+}
+
+fn escape_attr_value(value: &str) -> String {
+    value.chars().fold(String::with_capacity(value.len()), |mut acc, c| {
+        match c {
+            '&' => acc.push_str("&amp;"),
+            '"' => acc.push_str("&quot;"),
+            '\u{00A0}' => acc.push_str("&nbsp;"),
+            _ => acc.push(c),
+        }
+        acc
+    })
+}
+
+fn escape_text(value: &str) -> String {
+    value.chars().fold(String::with_capacity(value.len()), |mut acc, c| {
+        match c {
+            '&' => acc.push_str("&amp;"),
+            '<' => acc.push_str("&lt;"),
+            '>' => acc.push_str("&gt;"),
+            _ => acc.push(c),
+        }
+        acc
+    })
 }
 
 fn node_or_text_to_node(sink: &mut FlatSink, not: NodeOrText<NodeHandle>) -> NodeHandle {
@@ -114,13 +349,27 @@ impl TreeSink for FlatSink {
         self
     }
 
-    // TODO: Log this or something
-    fn parse_error(&mut self, msg: Cow<'static, str>) {}
-    fn set_quirks_mode(&mut self, mode: QuirksMode) {}
+    fn parse_error(&mut self, msg: Cow<'static, str>) {
+        self.errors.push(ParseError {
+            message: msg,
+            line: None,
+            column: None,
+        });
+    }
+    fn set_quirks_mode(&mut self, mode: QuirksMode) {
+        self.quirks_mode = mode;
+    }
 
     fn get_document(&mut self) -> Self::Handle { NodeHandle(0) }
     fn get_template_contents(&mut self, target: &Self::Handle) -> Self::Handle {
-        panic!("Templates not supported");
+        match self.node(*target).data {
+            NodeData::Element { template_contents: Some(contents), .. } => contents,
+            // Shouldn't happen -- html5ever only calls this on elements it
+            // created with `flags.template` set, which always get a
+            // `template_contents` document. Fall back to the document root
+            // rather than panicking and taking the whole NIF down with it.
+            _ => self.root,
+        }
     }
 
     fn same_node(&self, x: &Self::Handle, y: &Self::Handle) -> bool { x == y }
@@ -171,6 +420,10 @@ impl TreeSink for FlatSink {
     }
 
     fn append_doctype_to_document(&mut self, name: StrTendril, public_id: StrTendril, system_id: StrTendril) {
+        if self.opts.drop_doctype {
+            return;
+        }
+
         let doctype = self.make_node(NodeData::DocType {
             name: name,
             public_id: public_id,
@@ -213,7 +466,8 @@ impl TreeSink for FlatSink {
     }
 
     fn mark_script_already_started(&mut self, _elem: &Self::Handle) {
-        panic!("unsupported");
+        // We don't execute scripts, so there's nothing to flag -- same
+        // no-op rcdom uses.
     }
 
     fn has_parent_node(&self, handle: &Self::Handle) -> bool {
@@ -229,6 +483,21 @@ impl TreeSink for FlatSink {
 
 }
 
+impl NifEncoder for ParseError {
+    fn encode<'a>(&self, env: NifEnv<'a>) -> NifTerm<'a> {
+        ::rustler::types::map::map_new(env)
+            .map_put(atoms::message().encode(env), (&*self.message).encode(env)).ok().unwrap()
+            .map_put(atoms::line().encode(env), match self.line {
+                Some(line) => line.encode(env),
+                None => atoms::nil().encode(env),
+            }).ok().unwrap()
+            .map_put(atoms::column().encode(env), match self.column {
+                Some(column) => column.encode(env),
+                None => atoms::nil().encode(env),
+            }).ok().unwrap()
+    }
+}
+
 impl NifEncoder for NodeHandle {
     fn encode<'a>(&self, env: NifEnv<'a>) -> NifTerm<'a> {
         self.0.encode(env)
@@ -249,7 +518,7 @@ impl NifEncoder for Node {
                 map
                     .map_put(atoms::type_().encode(env), atoms::document().encode(env)).ok().unwrap()
             }
-            NodeData::Element { ref attrs, ref name, .. } => {
+            NodeData::Element { ref attrs, ref name, ref template_contents, .. } => {
                 map
                     .map_put(atoms::type_().encode(env), atoms::element().encode(env)).ok().unwrap()
                     .map_put(atoms::children().encode(env), self.children.encode(env)).ok().unwrap()
@@ -257,6 +526,10 @@ impl NifEncoder for Node {
                     .map_put(atoms::attrs().encode(env), attrs.iter().map(|attr| {
                         (QNW(&attr.name), STW(&attr.value))
                     }).collect::<Vec<_>>().encode(env)).ok().unwrap()
+                    .map_put(atoms::template_contents().encode(env), match *template_contents {
+                        Some(handle) => handle.encode(env),
+                        None => atoms::nil().encode(env),
+                    }).ok().unwrap()
             }
             NodeData::Text { ref contents } => {
                 map
@@ -280,6 +553,7 @@ impl NifEncoder for Node {
 mod atoms {
     rustler_atoms! {
         atom nil;
+        atom error;
 
         atom type_ = "type";
         atom document;
@@ -296,6 +570,36 @@ mod atoms {
         atom children;
         atom contents;
         atom attrs;
+        atom template_contents;
+
+        atom errors;
+        atom message;
+        atom line;
+        atom column;
+
+        atom quirks_mode;
+        atom quirks;
+        atom limited_quirks;
+        atom no_quirks;
+    }
+}
+
+fn quirks_mode_to_term<'a>(env: NifEnv<'a>, mode: QuirksMode) -> NifTerm<'a> {
+    match mode {
+        QuirksMode::Quirks => atoms::quirks().encode(env),
+        QuirksMode::LimitedQuirks => atoms::limited_quirks().encode(env),
+        QuirksMode::NoQuirks => atoms::no_quirks().encode(env),
+    }
+}
+
+pub fn serialize_to_term<'a>(env: NifEnv<'a>, sink: &FlatSink, handle: NodeHandle) -> NifTerm<'a> {
+    sink.serialize(handle).encode(env)
+}
+
+pub fn select_to_term<'a>(env: NifEnv<'a>, sink: &FlatSink, selector: &str) -> NifTerm<'a> {
+    match select::select(sink, sink.root, selector) {
+        Ok(handles) => handles.encode(env),
+        Err(reason) => (atoms::error(), reason).encode(env),
     }
 }
 
@@ -308,4 +612,382 @@ pub fn flat_sink_to_term<'a>(env: NifEnv<'a>, sink: &FlatSink) -> NifTerm<'a> {
     ::rustler::types::map::map_new(env)
         .map_put(atoms::nodes().encode(env), nodes).ok().unwrap()
         .map_put(atoms::root().encode(env), sink.root.encode(env)).ok().unwrap()
+        .map_put(atoms::errors().encode(env), sink.errors.encode(env)).ok().unwrap()
+        .map_put(atoms::quirks_mode().encode(env), quirks_mode_to_term(env, sink.quirks_mode)).ok().unwrap()
+}
+
+/// CSS-selector matching over a `FlatSink` arena. `select` does a pre-order
+/// walk from a root handle and collects every element handle matched by any
+/// selector in the list, parsing the selector string through the `selectors`
+/// crate's own grammar instead of hand-rolling one.
+pub mod select {
+    use super::{ FlatSink, NodeHandle, NodeData };
+
+    use selectors::{ Element, SelectorList };
+    use selectors::parser::{ SelectorParseErrorKind, SelectorImpl };
+    use selectors::attr::{ AttrSelectorOperation, CaseSensitivity, NamespaceConstraint };
+    use selectors::matching::{ self, ElementSelectorFlags, MatchingContext, MatchingMode, QuirksMode as MatchingQuirksMode };
+    use cssparser::{ Parser as CssParser, ParserInput, ToCss };
+
+    use html5ever::{ LocalName, Namespace };
+    use markup5ever::{ local_name, ns };
+
+    use std::fmt;
+
+    #[derive(Debug, Clone)]
+    pub struct FlatSelectorImpl;
+
+    impl SelectorImpl for FlatSelectorImpl {
+        type AttrValue = String;
+        type Identifier = LocalName;
+        type ClassName = LocalName;
+        type LocalName = LocalName;
+        type NamespaceUrl = Namespace;
+        type NamespacePrefix = LocalName;
+        type BorrowedLocalName = LocalName;
+        type BorrowedNamespaceUrl = Namespace;
+        type NonTSPseudoClass = NonTSPseudoClass;
+        type PseudoElement = PseudoElement;
+
+        type ExtraMatchingData = ();
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct NonTSPseudoClass;
+    impl selectors::parser::NonTSPseudoClass for NonTSPseudoClass {
+        type Impl = FlatSelectorImpl;
+        fn is_active_or_hover(&self) -> bool { false }
+        fn is_user_action_state(&self) -> bool { false }
+    }
+    impl ToCss for NonTSPseudoClass {
+        fn to_css<W: fmt::Write>(&self, _dest: &mut W) -> fmt::Result { Ok(()) }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct PseudoElement;
+    impl selectors::parser::PseudoElement for PseudoElement {
+        type Impl = FlatSelectorImpl;
+    }
+    impl ToCss for PseudoElement {
+        fn to_css<W: fmt::Write>(&self, _dest: &mut W) -> fmt::Result { Ok(()) }
+    }
+
+    struct FlatParser;
+    impl<'i> selectors::parser::Parser<'i> for FlatParser {
+        type Impl = FlatSelectorImpl;
+        type Error = SelectorParseErrorKind<'i>;
+    }
+
+    /// A `(&FlatSink, NodeHandle)` borrow pair standing in for a DOM element
+    /// reference, so the `selectors` matching engine can walk the flat arena
+    /// the same way it would walk a real tree.
+    #[derive(Copy, Clone)]
+    pub struct FlatElement<'a>(pub &'a FlatSink, pub NodeHandle);
+
+    impl<'a> fmt::Debug for FlatElement<'a> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "FlatElement({:?})", (self.1).0)
+        }
+    }
+
+    impl<'a> FlatElement<'a> {
+        fn sibling_index(&self) -> Option<(NodeHandle, usize)> {
+            let parent = self.0.node(self.1).parent?;
+            let index = self.0.node(parent).children.iter()
+                .position(|child| *child == self.1)?;
+            Some((parent, index))
+        }
+
+        fn with_attrs<R, F: FnOnce(&[html5ever::Attribute]) -> R>(&self, f: F, default: R) -> R {
+            match self.0.node(self.1).data {
+                NodeData::Element { ref attrs, .. } => f(attrs),
+                _ => default,
+            }
+        }
+    }
+
+    impl<'a> Element for FlatElement<'a> {
+        type Impl = FlatSelectorImpl;
+
+        fn opaque(&self) -> selectors::OpaqueElement {
+            selectors::OpaqueElement::new(self.0.node(self.1))
+        }
+
+        fn parent_element(&self) -> Option<Self> {
+            let parent = self.0.node(self.1).parent?;
+            match self.0.node(parent).data {
+                NodeData::Element { .. } => Some(FlatElement(self.0, parent)),
+                _ => None,
+            }
+        }
+
+        fn parent_node_is_shadow_root(&self) -> bool { false }
+        fn containing_shadow_host(&self) -> Option<Self> { None }
+        fn is_pseudo_element(&self) -> bool { false }
+
+        fn prev_sibling_element(&self) -> Option<Self> {
+            let (parent, index) = self.sibling_index()?;
+            self.0.node(parent).children[..index].iter().rev()
+                .map(|&h| FlatElement(self.0, h))
+                .find(|el| el.is_element())
+        }
+
+        fn next_sibling_element(&self) -> Option<Self> {
+            let (parent, index) = self.sibling_index()?;
+            self.0.node(parent).children[index + 1..].iter()
+                .map(|&h| FlatElement(self.0, h))
+                .find(|el| el.is_element())
+        }
+
+        fn is_html_element_in_html_document(&self) -> bool { true }
+
+        fn has_local_name(&self, local_name: &LocalName) -> bool {
+            match self.0.node(self.1).data {
+                NodeData::Element { ref name, .. } => &name.local == local_name,
+                _ => false,
+            }
+        }
+
+        fn has_namespace(&self, ns: &Namespace) -> bool {
+            match self.0.node(self.1).data {
+                NodeData::Element { ref name, .. } => &name.ns == ns,
+                _ => false,
+            }
+        }
+
+        fn is_same_type(&self, other: &Self) -> bool {
+            self.has_local_name(&other.local_name_owned()) && self.has_namespace(&other.namespace_owned())
+        }
+
+        fn attr_matches(
+            &self,
+            ns: &NamespaceConstraint<&Namespace>,
+            local_name: &LocalName,
+            operation: &AttrSelectorOperation<&String>,
+        ) -> bool {
+            self.with_attrs(|attrs| {
+                attrs.iter().any(|attr| {
+                    let name_matches = attr.name.local == *local_name && match *ns {
+                        NamespaceConstraint::Any => true,
+                        NamespaceConstraint::Specific(ns) => attr.name.ns == *ns,
+                    };
+                    name_matches && operation.eval_str(&attr.value)
+                })
+            }, false)
+        }
+
+        fn match_non_ts_pseudo_class<F>(
+            &self,
+            _pc: &NonTSPseudoClass,
+            _context: &mut MatchingContext<Self::Impl>,
+            _flags_setter: &mut F,
+        ) -> bool where F: FnMut(&Self, ElementSelectorFlags) {
+            false
+        }
+
+        fn match_pseudo_element(
+            &self,
+            _pe: &PseudoElement,
+            _context: &mut MatchingContext<Self::Impl>,
+        ) -> bool {
+            false
+        }
+
+        fn is_link(&self) -> bool {
+            self.has_local_name(&local_name!("a")) && self.with_attrs(|attrs| {
+                attrs.iter().any(|attr| &*attr.name.local == "href")
+            }, false)
+        }
+
+        fn is_html_slot_element(&self) -> bool { false }
+
+        fn has_id(&self, id: &LocalName, case_sensitivity: CaseSensitivity) -> bool {
+            self.with_attrs(|attrs| {
+                attrs.iter().any(|attr| {
+                    &*attr.name.local == "id" && case_sensitivity.eq(attr.value.as_bytes(), id.as_bytes())
+                })
+            }, false)
+        }
+
+        fn has_class(&self, name: &LocalName, case_sensitivity: CaseSensitivity) -> bool {
+            self.with_attrs(|attrs| {
+                attrs.iter()
+                    .filter(|attr| &*attr.name.local == "class")
+                    .flat_map(|attr| attr.value.split_whitespace())
+                    .any(|class| case_sensitivity.eq(class.as_bytes(), name.as_bytes()))
+            }, false)
+        }
+
+        fn is_empty(&self) -> bool {
+            self.0.node(self.1).children.is_empty()
+        }
+
+        fn is_root(&self) -> bool {
+            self.0.node(self.1).parent
+                .map(|parent| self.0.node(parent).parent.is_none())
+                .unwrap_or(false)
+        }
+    }
+
+    impl<'a> FlatElement<'a> {
+        fn is_element(&self) -> bool {
+            match self.0.node(self.1).data {
+                NodeData::Element { .. } => true,
+                _ => false,
+            }
+        }
+
+        fn local_name_owned(&self) -> LocalName {
+            match self.0.node(self.1).data {
+                NodeData::Element { ref name, .. } => name.local.clone(),
+                _ => LocalName::from(""),
+            }
+        }
+
+        fn namespace_owned(&self) -> Namespace {
+            match self.0.node(self.1).data {
+                NodeData::Element { ref name, .. } => name.ns.clone(),
+                _ => ns!(),
+            }
+        }
+    }
+
+    // In a quirks-mode document, class/#id selectors match
+    // ASCII-case-insensitively; `selectors` needs its own `QuirksMode` to
+    // know that, so translate html5ever's.
+    fn to_matching_quirks_mode(mode: html5ever::tree_builder::QuirksMode) -> MatchingQuirksMode {
+        match mode {
+            html5ever::tree_builder::QuirksMode::Quirks => MatchingQuirksMode::Quirks,
+            html5ever::tree_builder::QuirksMode::LimitedQuirks => MatchingQuirksMode::LimitedQuirks,
+            html5ever::tree_builder::QuirksMode::NoQuirks => MatchingQuirksMode::NoQuirks,
+        }
+    }
+
+    /// Parses `selector` once, then walks the arena in pre-order from `root`
+    /// collecting every element handle matched by any selector in the list.
+    /// Returns `Err` with a description of the parse failure for an
+    /// empty/invalid selector instead of panicking.
+    pub fn select(sink: &FlatSink, root: NodeHandle, selector: &str) -> Result<Vec<NodeHandle>, String> {
+        let mut input = ParserInput::new(selector);
+        let mut parser = CssParser::new(&mut input);
+        let selector_list = SelectorList::parse(&FlatParser, &mut parser)
+            .map_err(|e| format!("invalid selector {:?}: {:?}", selector, e.kind))?;
+
+        let mut matches = Vec::new();
+        let mut stack = vec![root];
+
+        while let Some(handle) = stack.pop() {
+            let node = sink.node(handle);
+
+            if let NodeData::Element { .. } = node.data {
+                let element = FlatElement(sink, handle);
+                let mut context = MatchingContext::new(
+                    MatchingMode::Normal,
+                    None,
+                    None,
+                    to_matching_quirks_mode(sink.quirks_mode),
+                );
+                let is_match = selector_list.0.iter().any(|selector| {
+                    matching::matches_selector(selector, 0, None, &element, &mut context, &mut |_, _| {})
+                });
+                if is_match {
+                    matches.push(handle);
+                }
+            }
+
+            for &child in node.children.iter().rev() {
+                stack.push(child);
+            }
+        }
+
+        Ok(matches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ FlatSink, FlatSinkOpts, NodeData };
+    use super::select::select;
+
+    use html5ever::QualName;
+    use markup5ever::{ local_name, ns };
+
+    fn parse(html: &str) -> FlatSink {
+        FlatSink::parse_document(html.as_bytes(), FlatSinkOpts::default())
+    }
+
+    #[test]
+    fn round_trips_a_simple_document() {
+        let sink = parse("<html><body><p>hello</p></body></html>");
+        let html = sink.serialize(sink.root);
+        assert!(html.contains("<p>hello</p>"));
+    }
+
+    #[test]
+    fn escapes_ampersand_quote_and_nbsp_in_attribute_values() {
+        let sink = parse("<html><body><a title=\"a&amp;b&quot;c&nbsp;d\"></a></body></html>");
+        let html = sink.serialize(sink.root);
+        assert!(html.contains("title=\"a&amp;b&quot;c&nbsp;d\""));
+    }
+
+    #[test]
+    fn self_closes_void_elements() {
+        let sink = parse("<html><body><br><img src=\"x.png\"></body></html>");
+        let html = sink.serialize(sink.root);
+        assert!(html.contains("<br />"));
+        assert!(html.contains("<img src=\"x.png\" />"));
+    }
+
+    #[test]
+    fn emits_script_contents_verbatim_instead_of_escaping_them() {
+        let sink = parse("<html><body><script>1 < 2 && true</script></body></html>");
+        let html = sink.serialize(sink.root);
+        assert!(html.contains("<script>1 < 2 && true</script>"));
+    }
+
+    #[test]
+    fn select_finds_matching_elements() {
+        let sink = parse("<html><body><div class=\"item\">a</div><div>b</div></body></html>");
+        let matches = select(&sink, sink.root, "div.item").unwrap();
+        assert_eq!(matches.len(), 1);
+    }
+
+    #[test]
+    fn select_returns_an_error_for_an_invalid_selector() {
+        let sink = parse("<html><body></body></html>");
+        assert!(select(&sink, sink.root, ">>>").is_err());
+    }
+
+    #[test]
+    fn parses_a_fragment_in_its_context_insertion_mode() {
+        let context = QualName::new(None, ns!(html), local_name!("tr"));
+        let sink = FlatSink::parse_fragment(b"<td>a</td>", context, Vec::new(), FlatSinkOpts::default());
+        let html = sink.serialize(sink.root);
+        assert!(html.contains("<td>a</td>"));
+    }
+
+    #[test]
+    fn exposes_template_contents_instead_of_panicking() {
+        let sink = parse("<html><body><template><span>hi</span></template></body></html>");
+        let template = select(&sink, sink.root, "template").unwrap()[0];
+
+        match sink.node(template).data {
+            NodeData::Element { template_contents: Some(contents), .. } => {
+                assert!(!sink.node(contents).children.is_empty());
+            }
+            _ => panic!("expected template_contents to be populated"),
+        }
+    }
+
+    #[test]
+    fn drop_doctype_skips_the_doctype_node() {
+        let opts = FlatSinkOpts { drop_doctype: true, ..FlatSinkOpts::default() };
+        let sink = FlatSink::parse_document(b"<!DOCTYPE html><html></html>", opts);
+
+        let has_doctype = sink.nodes.iter().any(|node| match node.data {
+            NodeData::DocType { .. } => true,
+            _ => false,
+        });
+        assert!(!has_doctype);
+    }
 }